@@ -29,15 +29,24 @@
 extern crate log;
 
 use std::net;
+use std::time;
+
+use std::sync::atomic;
 
 use std::collections::HashMap;
 
+use ring::digest;
+use ring::hmac;
 use ring::rand::*;
 
 const LOCAL_CONN_ID_LEN: usize = 16;
 
 const MAX_DATAGRAM_SIZE: usize = 1452;
 
+const TOKEN_TTL: time::Duration = time::Duration::from_secs(10);
+
+const TOKEN_TAG_LEN: usize = 32;
+
 const USAGE: &str = "Usage:
   server [options]
   server -h | --help
@@ -48,11 +57,53 @@ Options:
   --key <file>      TLS certificate key path [default: examples/cert.key]
   --root <dir>      Root directory [default: examples/root/]
   --name <str>      Name of the server [default: quic.tech]
+  --max-conns <num> Maximum number of concurrent connections [default: 1000]
   -h --help         Show this screen.
 ";
 
 type ConnMap = HashMap<Vec<u8>, (net::SocketAddr, Box<quiche::Connection>)>;
 
+/// Caps the number of connections the server handles at the same time.
+struct ConnectionLimiter {
+    max_connections: usize,
+    current: atomic::AtomicUsize,
+}
+
+impl ConnectionLimiter {
+    fn new(max_connections: usize) -> ConnectionLimiter {
+        ConnectionLimiter {
+            max_connections,
+            current: atomic::AtomicUsize::new(0),
+        }
+    }
+
+    /// Reserves a slot for a new connection.
+    ///
+    /// Returns false if the limit has already been reached.
+    fn acquire(&self) -> bool {
+        let mut current = self.current.load(atomic::Ordering::Relaxed);
+
+        loop {
+            if current >= self.max_connections {
+                return false;
+            }
+
+            match self.current.compare_exchange_weak(current, current + 1,
+                                                     atomic::Ordering::Relaxed,
+                                                     atomic::Ordering::Relaxed) {
+                Ok(_) => return true,
+
+                Err(v) => current = v,
+            }
+        }
+    }
+
+    /// Releases the slot held by a connection that went away.
+    fn release(&self) {
+        self.current.fetch_sub(1, atomic::Ordering::Relaxed);
+    }
+}
+
 fn main() {
     let mut buf = [0; 65535];
     let mut out = [0; MAX_DATAGRAM_SIZE];
@@ -75,6 +126,15 @@ fn main() {
 
     let mut connections = ConnMap::new();
 
+    let max_conns = args.get_str("--max-conns").parse::<usize>().unwrap();
+    let limiter = ConnectionLimiter::new(max_conns);
+
+    // Key used to authenticate retry tokens. It's not shared with anyone, so
+    // tokens minted by a previous instance of the server are rejected.
+    let mut token_secret = [0; 32];
+    SystemRandom::new().fill(&mut token_secret[..]).unwrap();
+    let token_key = hmac::SigningKey::new(&digest::SHA256, &token_secret);
+
     let mut config = quiche::Config::new(quiche::VERSION_DRAFT17).unwrap();
 
     config.load_cert_chain_from_pem_file(args.get_str("--cert")).unwrap();
@@ -168,7 +228,9 @@ fn main() {
                 if token.is_empty() {
                     warn!("Doing stateless retry");
 
-                    let new_token = mint_token(&hdr, &src);
+                    let new_token = mint_token_with_expiry(&hdr, &src,
+                                                           &token_key,
+                                                           TOKEN_TTL);
 
                     let len = quiche::retry(&hdr.scid, &hdr.dcid, &scid,
                                             &new_token, &mut out).unwrap();
@@ -178,13 +240,20 @@ fn main() {
                     continue;
                 }
 
-                let odcid = validate_token(&src, token);
+                let odcid = validate_token_with_expiry(&src, &token_key, token);
 
                 if odcid == None {
                     error!("Invalid address validation token");
                     continue;
                 }
 
+                // Stateless resets are not supported yet, so just drop the
+                // packet and let the client time out.
+                if !limiter.acquire() {
+                    warn!("Connection limit reached, dropping packet");
+                    continue;
+                }
+
                 debug!("New connection: dcid={} scid={}",
                        hex_dump(&hdr.dcid),
                        hex_dump(&hdr.scid));
@@ -261,6 +330,8 @@ fn main() {
 
             if c.is_closed() {
                 info!("{} connection collected {:?}", c.trace_id(), c.stats());
+
+                limiter.release();
             }
 
             !c.is_closed()
@@ -302,10 +373,7 @@ fn mint_token(hdr: &quiche::Header, src: &net::SocketAddr) -> Vec<u8> {
 
     token.extend_from_slice(b"quiche");
 
-    let addr = match src.ip() {
-        std::net::IpAddr::V4(a) => a.octets().to_vec(),
-        std::net::IpAddr::V6(a) => a.octets().to_vec(),
-    };
+    let addr = canonical_addr(src);
 
     token.extend_from_slice(&addr);
     token.extend_from_slice(&hdr.dcid);
@@ -324,10 +392,7 @@ fn validate_token<'a>(src: &net::SocketAddr, token: &'a [u8]) -> Option<&'a [u8]
 
     let token = &token[6..];
 
-    let addr = match src.ip() {
-        std::net::IpAddr::V4(a) => a.octets().to_vec(),
-        std::net::IpAddr::V6(a) => a.octets().to_vec(),
-    };
+    let addr = canonical_addr(src);
 
     if token.len() < addr.len() || &token[..addr.len()] != addr.as_slice() {
         return None;
@@ -338,6 +403,81 @@ fn validate_token<'a>(src: &net::SocketAddr, token: &'a [u8]) -> Option<&'a [u8]
     Some(&token[..])
 }
 
+/// Generates a retry token that expires after `ttl`.
+///
+/// The token produced by `mint_token()` is prefixed with its expiration time
+/// as seconds since the UNIX epoch, and followed by an HMAC-SHA256 tag over
+/// both, computed with `key`.
+fn mint_token_with_expiry(hdr: &quiche::Header, src: &net::SocketAddr,
+                          key: &hmac::SigningKey, ttl: time::Duration)
+                                                                -> Vec<u8> {
+    let expiry = unix_time() + ttl.as_secs();
+
+    let mut token = Vec::new();
+
+    token.extend_from_slice(&expiry.to_be_bytes());
+    token.extend_from_slice(&mint_token(hdr, src));
+
+    let tag = hmac::sign(key, &token);
+    token.extend_from_slice(tag.as_ref());
+
+    token
+}
+
+/// Validates a token generated by `mint_token_with_expiry()`.
+///
+/// Tokens with an invalid tag or which have expired are rejected. Otherwise
+/// the original destination connection ID is returned, as in
+/// `validate_token()`.
+fn validate_token_with_expiry<'a>(src: &net::SocketAddr, key: &hmac::SigningKey,
+                                  token: &'a [u8]) -> Option<&'a [u8]> {
+    if token.len() < 8 + TOKEN_TAG_LEN {
+        return None;
+    }
+
+    let (token, tag) = token.split_at(token.len() - TOKEN_TAG_LEN);
+
+    if hmac::verify_with_own_key(key, token, tag).is_err() {
+        return None;
+    }
+
+    let mut expiry = [0; 8];
+    expiry.copy_from_slice(&token[..8]);
+
+    if u64::from_be_bytes(expiry) < unix_time() {
+        return None;
+    }
+
+    validate_token(src, &token[8..])
+}
+
+fn unix_time() -> u64 {
+    time::SystemTime::now().duration_since(time::UNIX_EPOCH)
+                           .map(|d| d.as_secs())
+                           .unwrap_or(0)
+}
+
+/// Returns the octets of the source IP address, with IPv4-mapped IPv6
+/// addresses (`::ffff:a.b.c.d`) converted to plain IPv4 ones.
+///
+/// This way a token minted for a client is still valid when the same client is
+/// seen through a dual-stack socket, and vice versa.
+fn canonical_addr(src: &net::SocketAddr) -> Vec<u8> {
+    match src.ip() {
+        std::net::IpAddr::V4(a) => a.octets().to_vec(),
+
+        std::net::IpAddr::V6(a) => {
+            let octets = a.octets();
+
+            if octets[..10] == [0; 10] && octets[10..12] == [0xff, 0xff] {
+                octets[12..].to_vec()
+            } else {
+                octets.to_vec()
+            }
+        },
+    }
+}
+
 fn hex_dump(buf: &[u8]) -> String {
     let vec: Vec<String> = buf.iter()
                               .map(|b| format!("{:02x}", b))
@@ -345,3 +485,97 @@ fn hex_dump(buf: &[u8]) -> String {
 
     vec.join("")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_ipv4_mapped_addr() {
+        let hdr = quiche::Header {
+            ty: quiche::Type::Initial,
+            version: quiche::VERSION_DRAFT17,
+            dcid: vec![0xba; 16],
+            scid: vec![0xab; 16],
+            pkt_num: 0,
+            pkt_num_len: 0,
+            odcid: None,
+            token: None,
+            versions: None,
+            key_phase: false,
+        };
+
+        let v4: net::SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let mapped: net::SocketAddr = "[::ffff:127.0.0.1]:4433".parse().unwrap();
+        let v6: net::SocketAddr = "[::1]:4433".parse().unwrap();
+
+        let token = mint_token(&hdr, &v4);
+        assert_eq!(validate_token(&mapped, &token), Some(&hdr.dcid[..]));
+        assert_eq!(validate_token(&v6, &token), None);
+
+        let token = mint_token(&hdr, &mapped);
+        assert_eq!(validate_token(&v4, &token), Some(&hdr.dcid[..]));
+    }
+
+    #[test]
+    fn token_with_expiry() {
+        let hdr = quiche::Header {
+            ty: quiche::Type::Initial,
+            version: quiche::VERSION_DRAFT17,
+            dcid: vec![0xba; 16],
+            scid: vec![0xab; 16],
+            pkt_num: 0,
+            pkt_num_len: 0,
+            odcid: None,
+            token: None,
+            versions: None,
+            key_phase: false,
+        };
+
+        let src: net::SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let other: net::SocketAddr = "127.0.0.2:4433".parse().unwrap();
+
+        let key = hmac::SigningKey::new(&digest::SHA256, &[0xba; 32]);
+        let other_key = hmac::SigningKey::new(&digest::SHA256, &[0xab; 32]);
+
+        let mut token = mint_token_with_expiry(&hdr, &src, &key,
+                                               time::Duration::from_secs(10));
+
+        assert_eq!(validate_token_with_expiry(&src, &key, &token),
+                   Some(&hdr.dcid[..]));
+        assert_eq!(validate_token_with_expiry(&other, &key, &token), None);
+        assert_eq!(validate_token_with_expiry(&src, &other_key, &token), None);
+
+        // Tampering with the expiration time invalidates the tag.
+        token[0] = 0xff;
+        assert_eq!(validate_token_with_expiry(&src, &key, &token), None);
+
+        // Expired token.
+        let expiry = unix_time() - 1;
+
+        let mut token = Vec::new();
+        token.extend_from_slice(&expiry.to_be_bytes());
+        token.extend_from_slice(&mint_token(&hdr, &src));
+
+        let tag = hmac::sign(&key, &token);
+        token.extend_from_slice(tag.as_ref());
+
+        assert_eq!(validate_token_with_expiry(&src, &key, &token), None);
+
+        assert_eq!(validate_token_with_expiry(&src, &key, b"quiche"), None);
+    }
+
+    #[test]
+    fn connection_limiter() {
+        let limiter = ConnectionLimiter::new(2);
+
+        assert!(limiter.acquire());
+        assert!(limiter.acquire());
+        assert!(!limiter.acquire());
+
+        limiter.release();
+
+        assert!(limiter.acquire());
+        assert!(!limiter.acquire());
+    }
+}
@@ -100,12 +100,27 @@ int quiche_config_load_cert_chain_from_pem_file(quiche_config *config,
 int quiche_config_load_priv_key_from_pem_file(quiche_config *config,
                                               const char *path);
 
+// Configures the certificate chain and private key for a virtual host.
+int quiche_config_add_virtual_host(quiche_config *config,
+                                   const char *server_name,
+                                   const char *cert, const char *key);
+
+// Configures the 48-byte key used to encrypt and decrypt session tickets.
+int quiche_config_set_session_ticket_key(quiche_config *config,
+                                         const uint8_t *key, size_t key_len);
+
 // Configures whether to verify the peer's certificate.
 void quiche_config_verify_peer(quiche_config *config, bool v);
 
 // Enables logging of secrets.
 void quiche_config_log_keys(quiche_config *config);
 
+// Enables logging of secrets to the given file.
+int quiche_config_set_keylog_file(quiche_config *config, const char *path);
+
+// Sets the trace ID of connections created with this configuration.
+void quiche_config_set_trace_id(quiche_config *config, const char *v);
+
 // Sets the `idle_timeout` transport parameter.
 void quiche_config_set_idle_timeout(quiche_config *config, uint64_t v);
 
@@ -196,6 +211,14 @@ ssize_t quiche_conn_stream_recv(quiche_conn *conn, uint64_t stream_id,
 ssize_t quiche_conn_stream_send(quiche_conn *conn, uint64_t stream_id,
                                 const uint8_t *buf, size_t buf_len, bool fin);
 
+// Returns true if all the data has been read from the specified stream.
+bool quiche_conn_stream_finished(quiche_conn *conn, uint64_t stream_id);
+
+// Returns the number of streams that can be created before the peer's stream
+// count limit is reached.
+uint64_t quiche_conn_peer_streams_left_bidi(quiche_conn *conn);
+uint64_t quiche_conn_peer_streams_left_uni(quiche_conn *conn);
+
 // An iterator over the streams that have outstanding data to read.
 typedef struct Readable quiche_readable;
 
@@ -219,6 +242,9 @@ void quiche_conn_on_timeout(quiche_conn *conn);
 int quiche_conn_close(quiche_conn *conn, bool app, uint16_t err,
                       const uint8_t *reason, size_t reason_len);
 
+// Initiates a key update.
+int quiche_conn_initiate_key_update(quiche_conn *conn);
+
 // Returns true if the connection handshake is complete.
 bool quiche_conn_is_established(quiche_conn *conn);
 
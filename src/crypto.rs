@@ -231,6 +231,72 @@ impl Seal {
     }
 }
 
+/// Traffic secrets of the 1-RTT packet number space.
+///
+/// These are needed to derive the packet protection keys of the next key
+/// phase. Header protection keys are not changed by a key update, so they
+/// are carried over as they are.
+pub struct Secrets {
+    alg: Algorithm,
+
+    read_secret: Vec<u8>,
+    read_hp_key: Vec<u8>,
+
+    write_secret: Vec<u8>,
+    write_hp_key: Vec<u8>,
+}
+
+impl Secrets {
+    pub fn new(alg: Algorithm, read_secret: &[u8], read_hp_key: &[u8],
+               write_secret: &[u8], write_hp_key: &[u8]) -> Secrets {
+        Secrets {
+            alg,
+
+            read_secret: Vec::from(read_secret),
+            read_hp_key: Vec::from(read_hp_key),
+
+            write_secret: Vec::from(write_secret),
+            write_hp_key: Vec::from(write_hp_key),
+        }
+    }
+
+    /// Derives the secrets of the next key phase, along with the packet
+    /// protection keys for both directions.
+    pub fn next(&self) -> Result<(Open, Seal, Secrets)> {
+        let key_len = self.alg.key_len();
+        let nonce_len = self.alg.nonce_len();
+
+        let mut key = vec![0; key_len];
+        let mut iv = vec![0; nonce_len];
+
+        let read_secret = derive_next_secret(self.alg, &self.read_secret)?;
+
+        derive_pkt_key(self.alg, &read_secret, &mut key)?;
+        derive_pkt_iv(self.alg, &read_secret, &mut iv)?;
+
+        let open = Open::new(self.alg, &key, &iv, &self.read_hp_key)?;
+
+        let write_secret = derive_next_secret(self.alg, &self.write_secret)?;
+
+        derive_pkt_key(self.alg, &write_secret, &mut key)?;
+        derive_pkt_iv(self.alg, &write_secret, &mut iv)?;
+
+        let seal = Seal::new(self.alg, &key, &iv, &self.write_hp_key)?;
+
+        let secrets = Secrets {
+            alg: self.alg,
+
+            read_secret,
+            read_hp_key: self.read_hp_key.clone(),
+
+            write_secret,
+            write_hp_key: self.write_hp_key.clone(),
+        };
+
+        Ok((open, seal, secrets))
+    }
+}
+
 pub fn derive_initial_key_material(cid: &[u8], is_server: bool)
                                                     -> Result<(Open, Seal)> {
     let mut secret: [u8; 32] =  [0; 32];
@@ -330,6 +396,17 @@ pub fn derive_pkt_iv(aead: Algorithm, secret: &[u8], out: &mut [u8])
     hkdf_expand_label(&secret, LABEL, &mut out[..nonce_len])
 }
 
+fn derive_next_secret(aead: Algorithm, secret: &[u8]) -> Result<Vec<u8>> {
+    const LABEL: &[u8] = b"traffic upd";
+
+    let mut out = vec![0; secret.len()];
+
+    let secret = hmac::SigningKey::new(aead.get_ring_digest(), secret);
+    hkdf_expand_label(&secret, LABEL, &mut out)?;
+
+    Ok(out)
+}
+
 fn hkdf_expand_label(prk: &hmac::SigningKey, label: &[u8],  out: &mut [u8])
                                                             -> Result<()> {
     const LABEL_PREFIX: &[u8] = b"tls13 ";
@@ -511,4 +588,33 @@ mod tests {
         ];
         assert_eq!(&hdr_key, &expected_server_hdr_key);
     }
+
+    #[test]
+    fn next_secrets() {
+        let aead = Algorithm::AES128_GCM;
+
+        let c_secret = [0x11; 32];
+        let s_secret = [0x22; 32];
+        let hp_key = [0x33; 16];
+
+        let client = Secrets::new(aead, &s_secret, &hp_key, &c_secret, &hp_key);
+        let server = Secrets::new(aead, &c_secret, &hp_key, &s_secret, &hp_key);
+
+        let (_, c_seal, client) = client.next().unwrap();
+        let (s_open, _, server) = server.next().unwrap();
+
+        assert_ne!(client.write_secret, c_secret.to_vec());
+        assert_eq!(client.write_secret, server.read_secret);
+        assert_eq!(client.read_secret, server.write_secret);
+
+        let mut buf = [0; 21];
+        buf[..5].copy_from_slice(b"hello");
+
+        let len = c_seal.seal_with_u64_counter(1, b"hdr", &mut buf).unwrap();
+        assert_eq!(len, 21);
+
+        let len = s_open.open_with_u64_counter(1, b"hdr", &mut buf).unwrap();
+        assert_eq!(&buf[..len], b"hello");
+    }
+
 }
@@ -101,6 +101,41 @@ pub extern fn quiche_config_load_priv_key_from_pem_file(config: &mut Config,
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_config_add_virtual_host(config: &mut Config,
+                                             server_name: *const c_char,
+                                             cert: *const c_char,
+                                             key: *const c_char) -> c_int {
+    let server_name = unsafe {
+        ffi::CStr::from_ptr(server_name).to_str().unwrap()
+    };
+    let cert = unsafe { ffi::CStr::from_ptr(cert).to_str().unwrap() };
+    let key = unsafe { ffi::CStr::from_ptr(key).to_str().unwrap() };
+
+    match config.add_virtual_host(server_name, cert, key) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_session_ticket_key(config: &mut Config,
+                                                   key: *const u8,
+                                                   key_len: usize) -> c_int {
+    if key_len != 48 {
+        return Error::TlsFail.to_c() as c_int;
+    }
+
+    let key = unsafe { &*(key as *const [u8; 48]) };
+
+    match config.set_session_ticket_key(key) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_config_verify_peer(config: &mut Config, v: bool) {
     config.verify_peer(v);
@@ -111,6 +146,25 @@ pub extern fn quiche_config_log_keys(config: &mut Config) {
     config.log_keys();
 }
 
+#[no_mangle]
+pub extern fn quiche_config_set_keylog_file(config: &mut Config,
+                                            path: *const c_char) -> c_int {
+    let path = unsafe { ffi::CStr::from_ptr(path).to_str().unwrap() };
+
+    match config.set_keylog_file(path) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_trace_id(config: &mut Config, v: *const c_char) {
+    let v = unsafe { ffi::CStr::from_ptr(v).to_str().unwrap() };
+
+    config.set_trace_id(v);
+}
+
 #[no_mangle]
 pub extern fn quiche_config_set_idle_timeout(config: &mut Config, v: u64) {
     config.set_idle_timeout(v);
@@ -385,6 +439,22 @@ pub extern fn quiche_conn_stream_send(conn: &mut Connection, stream_id: u64,
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_stream_finished(conn: &mut Connection,
+                                          stream_id: u64) -> bool {
+    conn.stream_finished(stream_id)
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_peer_streams_left_bidi(conn: &mut Connection) -> u64 {
+    conn.peer_streams_left_bidi()
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_peer_streams_left_uni(conn: &mut Connection) -> u64 {
+    conn.peer_streams_left_uni()
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_readable(conn: &mut Connection) -> *mut Readable {
     let iter = conn.readable();
@@ -418,6 +488,15 @@ pub extern fn quiche_conn_close(conn: &mut Connection, app: bool, err: u16,
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_initiate_key_update(conn: &mut Connection) -> c_int {
+    match conn.initiate_key_update() {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_timeout_as_nanos(conn: &mut Connection) -> u64 {
     match conn.timeout() {
@@ -174,7 +174,10 @@
 extern crate log;
 
 use std::cmp;
+use std::fs;
 use std::mem;
+use std::rc;
+use std::sync;
 use std::time;
 
 use std::collections::hash_map;
@@ -280,6 +283,10 @@ pub struct Config {
     tls_ctx: tls::Context,
 
     application_protos: Vec<Vec<u8>>,
+
+    keylog: Option<sync::Arc<fs::File>>,
+
+    trace_id: Option<String>,
 }
 
 impl Config {
@@ -293,6 +300,8 @@ impl Config {
             version,
             tls_ctx,
             application_protos: Vec::new(),
+            keylog: None,
+            trace_id: None,
         })
     }
 
@@ -313,6 +322,39 @@ impl Config {
                     .map_err(|_| Error::TlsFail)
     }
 
+    /// Configures the certificate chain and private key for a virtual host.
+    ///
+    /// On the server, when a client indicates `server_name` using SNI, the
+    /// certificate chain in `cert` and the private key in `key` are used
+    /// instead of the default ones. Both files are parsed as PEM, like in
+    /// [`load_cert_chain_from_pem_file()`] and
+    /// [`load_priv_key_from_pem_file()`]. Server names are matched
+    /// case-insensitively, and clients indicating an unknown name are served
+    /// the default certificate.
+    ///
+    /// [`load_cert_chain_from_pem_file()`]: struct.Config.html#method.load_cert_chain_from_pem_file
+    /// [`load_priv_key_from_pem_file()`]: struct.Config.html#method.load_priv_key_from_pem_file
+    pub fn add_virtual_host(&mut self, server_name: &str, cert: &str,
+                            key: &str) -> Result<()> {
+        self.tls_ctx.add_vhost(server_name, cert, key)
+                    .map_err(|_| Error::TlsFail)
+    }
+
+    /// Configures the key used to encrypt and decrypt session tickets.
+    ///
+    /// The 48 bytes of `key` are made of a 16-byte key name, followed by a
+    /// 16-byte HMAC secret and a 16-byte AES key. By default a random key is
+    /// generated when the configuration is created.
+    ///
+    /// Ticket keys are shared by all the connections created from this
+    /// configuration, so calling this again replaces the key for subsequent
+    /// handshakes, which allows rotating it at runtime. Tickets issued with
+    /// the previous key can't be used for resumption anymore.
+    pub fn set_session_ticket_key(&mut self, key: &[u8; 48]) -> Result<()> {
+        self.tls_ctx.set_ticket_key(key)
+                    .map_err(|_| Error::TlsFail)
+    }
+
     /// Configures whether to verify the peer's certificate.
     pub fn verify_peer(&mut self, verify: bool) {
         self.tls_ctx.set_verify(verify);
@@ -329,6 +371,28 @@ impl Config {
         self.tls_ctx.enable_keylog();
     }
 
+    /// Enables logging of secrets to the given file.
+    ///
+    /// A connection's cryptographic secrets will be appended in the [keylog]
+    /// format to the file at `path`, which is created if it doesn't exist.
+    /// Unlike [`log_keys()`], this doesn't depend on the `SSLKEYLOGFILE`
+    /// environment variable.
+    ///
+    /// [keylog]: https://developer.mozilla.org/en-US/docs/Mozilla/Projects/NSS/Key_Log_Format
+    /// [`log_keys()`]: struct.Config.html#method.log_keys
+    pub fn set_keylog_file(&mut self, path: &str) -> Result<()> {
+        let file = fs::OpenOptions::new().create(true)
+                                         .append(true)
+                                         .open(path)
+                                         .map_err(|_| Error::TlsFail)?;
+
+        self.keylog = Some(sync::Arc::new(file));
+
+        self.tls_ctx.enable_keylog();
+
+        Ok(())
+    }
+
     /// Configures the list of support application protocolos.
     ///
     /// On the client this configures the list of protocols to send to the
@@ -343,11 +407,51 @@ impl Config {
                     .map_err(|_| Error::TlsFail)
     }
 
+    /// Sets the trace ID of connections created with this configuration.
+    ///
+    /// By default a connection's [`trace_id()`] is derived from its source
+    /// connection ID, which is usually random. Setting a fixed value is mostly
+    /// useful to get reproducible logs, e.g. in tests.
+    ///
+    /// [`trace_id()`]: struct.Connection.html#method.trace_id
+    pub fn set_trace_id(&mut self, v: &str) {
+        self.trace_id = Some(v.to_string());
+    }
+
     /// Sets the `idle_timeout` transport parameter.
     pub fn set_idle_timeout(&mut self, v: u64) {
         self.local_transport_params.idle_timeout = v;
     }
 
+    /// Sets the `idle_timeout` transport parameter from a [`Duration`].
+    ///
+    /// The transport parameter is expressed in seconds, so sub-second
+    /// durations are rounded up to the next whole second. A zero duration
+    /// disables the idle timeout.
+    ///
+    /// Returns [`InvalidTransportParam`] if the duration, rounded up to whole
+    /// seconds, is larger than 2^62 - 1, the largest value that can be
+    /// encoded as a transport parameter. The configuration is left unchanged
+    /// in that case.
+    ///
+    /// [`Duration`]: https://doc.rust-lang.org/std/time/struct.Duration.html
+    /// [`InvalidTransportParam`]: enum.Error.html#variant.InvalidTransportParam
+    pub fn set_max_idle_timeout(&mut self, v: time::Duration) -> Result<()> {
+        let mut secs = v.as_secs();
+
+        if v.subsec_nanos() > 0 {
+            secs = secs.saturating_add(1);
+        }
+
+        if secs > octets::MAX_VAR_INT {
+            return Err(Error::InvalidTransportParam);
+        }
+
+        self.set_idle_timeout(secs);
+
+        Ok(())
+    }
+
     /// Sets the `stateless_reset_token` transport parameter.
     pub fn set_stateless_reset_token(&mut self, v: &[u8; 16]) {
         self.local_transport_params.stateless_reset_token = Some(v.to_vec());
@@ -427,6 +531,10 @@ pub struct Connection {
 
     application_protos: Vec<Vec<u8>>,
 
+    keylog: Option<sync::Arc<fs::File>>,
+
+    vhosts: rc::Rc<tls::VirtualHosts>,
+
     sent_count: usize,
     lost_count: usize,
 
@@ -445,6 +553,11 @@ pub struct Connection {
     peer_max_streams_bidi: usize,
     peer_max_streams_uni: usize,
 
+    // One more than the highest stream index opened locally. Opening a stream
+    // implicitly opens all lower-numbered streams of the same type.
+    local_opened_streams_bidi: usize,
+    local_opened_streams_uni: usize,
+
     odcid: Option<Vec<u8>>,
 
     token: Option<Vec<u8>>,
@@ -456,6 +569,8 @@ pub struct Connection {
 
     challenge: Option<Vec<u8>>,
 
+    created: time::Instant,
+
     idle_timer: Option<time::Instant>,
 
     draining_timer: Option<time::Instant>,
@@ -484,7 +599,13 @@ pub struct Connection {
 /// client sent before a stateless retry (this is only required when using
 /// the [`retry()`] function).
 ///
+/// When `odcid` is provided, the client's first Initial packet must carry a
+/// non-empty token, as it would after a retry. Otherwise [`recv()`] returns
+/// [`InvalidPacket`].
+///
 /// [`retry()`]: fn.retry.html
+/// [`recv()`]: struct.Connection.html#method.recv
+/// [`InvalidPacket`]: enum.Error.html#variant.InvalidPacket
 pub fn accept(scid: &[u8], odcid: Option<&[u8]>, config: &mut Config) -> Result<Box<Connection>> {
     let conn = Connection::new(scid, odcid, config, true)?;
 
@@ -551,7 +672,8 @@ impl Connection {
             dcid: Vec::new(),
             scid: scid.to_vec(),
 
-            trace_id: scid_as_hex.join(""),
+            trace_id: config.trace_id.clone()
+                                     .unwrap_or_else(|| scid_as_hex.join("")),
 
             initial: packet::PktNumSpace::new(crypto::Level::Initial),
             handshake: packet::PktNumSpace::new(crypto::Level::Handshake),
@@ -567,6 +689,10 @@ impl Connection {
 
             application_protos: config.application_protos.clone(),
 
+            keylog: config.keylog.clone(),
+
+            vhosts: config.tls_ctx.vhosts(),
+
             sent_count: 0,
             lost_count: 0,
 
@@ -587,6 +713,9 @@ impl Connection {
             peer_max_streams_bidi: 0,
             peer_max_streams_uni: 0,
 
+            local_opened_streams_bidi: 0,
+            local_opened_streams_uni: 0,
+
             odcid: None,
 
             token: None,
@@ -598,6 +727,8 @@ impl Connection {
 
             challenge: None,
 
+            created: time::Instant::now(),
+
             idle_timer: None,
 
             draining_timer: None,
@@ -819,6 +950,18 @@ impl Connection {
 
         // Derive initial secrets on the server.
         if !self.derived_initial_secrets {
+            // An original destination connection ID is only expected after a
+            // stateless retry, in which case the client's Initial must carry
+            // the token it received.
+            if self.is_server &&
+               self.local_transport_params.original_connection_id.is_some() &&
+               hdr.token.as_ref().map_or(true, |t| t.is_empty()) {
+                trace!("{} odcid set but no retry token in Initial packet",
+                       self.trace_id);
+
+                return Err(Error::InvalidPacket);
+            }
+
             let (aead_open, aead_seal) =
                 crypto::derive_initial_key_material(&hdr.dcid, self.is_server)?;
 
@@ -861,9 +1004,53 @@ impl Connection {
         trace!("{} rx pkt {:?} len={} pn={}", self.trace_id, hdr,
                payload_len, pn);
 
-        let mut payload = packet::decrypt_pkt(&mut b, pn, hdr.pkt_num_len,
+        let tag_len = aead.alg().tag_len();
+
+        let mut payload = if hdr.ty != packet::Type::Application ||
+                             hdr.key_phase == space.key_phase {
+            let payload = packet::decrypt_pkt(&mut b, pn, hdr.pkt_num_len,
                                               payload_len, &aead)?;
 
+            // The peer switched to the keys of our key update.
+            if hdr.ty == packet::Type::Application && space.key_update_pending {
+                space.key_update_pending = false;
+                space.key_phase_start_pkt_num = pn;
+            }
+
+            payload
+        } else if space.key_update_pending ||
+                  pn < space.key_phase_start_pkt_num {
+            // The packet was protected with the keys of the previous key
+            // phase, either because the peer hasn't yet noticed our key
+            // update, or because it was reordered.
+            let aead = match space.prev_crypto_open {
+                Some(ref v) => v,
+
+                None => return Err(Error::CryptoFail),
+            };
+
+            packet::decrypt_pkt(&mut b, pn, hdr.pkt_num_len, payload_len,
+                                &aead)?
+        } else {
+            // The peer initiated a key update.
+            let keys = match space.secrets {
+                Some(ref v) => v.next()?,
+
+                None => return Err(Error::CryptoFail),
+            };
+
+            let payload = packet::decrypt_pkt(&mut b, pn, hdr.pkt_num_len,
+                                              payload_len, &keys.0)?;
+
+            trace!("{} key update initiated by peer pn={}",
+                   self.trace_id, pn);
+
+            space.update_keys(keys);
+            space.key_phase_start_pkt_num = pn;
+
+            payload
+        };
+
         if space.recv_pkt_num.contains(pn) {
             trace!("{} ignored duplicate packet {}", self.trace_id, pn);
             return Err(Error::Done);
@@ -1107,11 +1294,15 @@ impl Connection {
 
         space.largest_rx_pkt_num = cmp::max(space.largest_rx_pkt_num, pn);
 
-        self.idle_timer =
-            Some(now + time::Duration::from_secs(
-                self.local_transport_params.idle_timeout));
+        let idle_timeout = self.effective_idle_timeout();
 
-        let read = b.off() + aead.alg().tag_len();
+        self.idle_timer = if idle_timeout > time::Duration::new(0, 0) {
+            Some(now + idle_timeout)
+        } else {
+            None
+        };
+
+        let read = b.off() + tag_len;
 
         // On the server, drop initial state after receiving and successfully
         // processing an Handshake packet.
@@ -1222,7 +1413,7 @@ impl Connection {
             odcid: None,
             token: self.token.clone(),
             versions: None,
-            key_phase: false,
+            key_phase: space.key_phase,
         };
 
         hdr.to_bytes(&mut b)?;
@@ -1551,13 +1742,27 @@ impl Connection {
                     return Err(Error::InvalidStreamState);
                 }
 
-                // Enforce stream count limits.
+                // Enforce stream count limits. These apply to the stream ID
+                // rather than to the number of streams actually used.
+                let opened = (stream_id >> 2) + 1;
+
                 if stream::is_bidi(stream_id) {
-                    self.peer_max_streams_bidi.checked_sub(1)
-                                              .ok_or(Error::StreamLimit)?;
+                    if opened > self.peer_max_streams_bidi as u64 {
+                        return Err(Error::StreamLimit);
+                    }
+
+                    // This can't truncate since it's within the peer's limit.
+                    self.local_opened_streams_bidi =
+                        cmp::max(self.local_opened_streams_bidi,
+                                 opened as usize);
                 } else {
-                    self.peer_max_streams_uni.checked_sub(1)
-                                             .ok_or(Error::StreamLimit)?;
+                    if opened > self.peer_max_streams_uni as u64 {
+                        return Err(Error::StreamLimit);
+                    }
+
+                    self.local_opened_streams_uni =
+                        cmp::max(self.local_opened_streams_uni,
+                                 opened as usize);
                 }
 
                 let s = stream::Stream::new(max_rx_data, max_tx_data);
@@ -1574,11 +1779,71 @@ impl Connection {
         Ok(buf.len())
     }
 
+    /// Returns true if all the data has been read from the specified stream.
+    ///
+    /// This instructs the application that all the data received from the
+    /// peer on the stream has been read, and there won't be anymore in the
+    /// future. A partial read that happens to drain the stream's buffer does
+    /// not make the stream finished until the peer's fin has been seen.
+    pub fn stream_finished(&self, stream_id: u64) -> bool {
+        match self.streams.get(&stream_id) {
+            Some(s) => s.is_fin(),
+
+            None => false,
+        }
+    }
+
+    /// Returns the number of bidirectional streams that can be created
+    /// before the peer's stream count limit is reached.
+    ///
+    /// This decreases as new local streams are opened with
+    /// [`stream_send()`], and increases when the peer raises the limit with a
+    /// MAX_STREAMS frame. Since the limit applies to stream IDs, opening a
+    /// stream also uses up the budget of any lower-numbered streams that
+    /// haven't been opened yet.
+    ///
+    /// [`stream_send()`]: struct.Connection.html#method.stream_send
+    pub fn peer_streams_left_bidi(&self) -> u64 {
+        self.peer_max_streams_bidi
+            .saturating_sub(self.local_opened_streams_bidi) as u64
+    }
+
+    /// Returns the number of unidirectional streams that can be created
+    /// before the peer's stream count limit is reached.
+    ///
+    /// See [`peer_streams_left_bidi()`] for details.
+    ///
+    /// [`peer_streams_left_bidi()`]: struct.Connection.html#method.peer_streams_left_bidi
+    pub fn peer_streams_left_uni(&self) -> u64 {
+        self.peer_max_streams_uni
+            .saturating_sub(self.local_opened_streams_uni) as u64
+    }
+
     /// Creates an iterator over streams that have outstanding data to read.
     pub fn readable(&mut self) -> Readable {
         stream::Readable::new(&self.streams)
     }
 
+    /// Returns true if there might be packets to send.
+    ///
+    /// When this returns `false`, calling [`send()`] would just return
+    /// [`Done`], so the application can skip its send loop. The opposite is
+    /// not guaranteed: [`send()`] might still have nothing to send, for
+    /// example when the congestion window is full. While the handshake is in
+    /// progress this always returns `true`.
+    ///
+    /// [`send()`]: struct.Connection.html#method.send
+    /// [`Done`]: enum.Error.html#variant.Done
+    pub fn has_pending_send(&self) -> bool {
+        if self.draining {
+            return false;
+        }
+
+        !self.handshake_completed ||
+            self.error.is_some() || self.app_error.is_some() ||
+            self.select_egress_pkt_type().is_ok()
+    }
+
     /// Returns the amount of time until the next timeout event.
     ///
     /// Once the given duration has elapsed, the [`on_timeout()`] method should
@@ -1684,6 +1949,37 @@ impl Connection {
         Ok(())
     }
 
+    /// Initiates a key update.
+    ///
+    /// The packet protection keys used for 1-RTT packets are replaced with
+    /// freshly derived ones, and the key phase bit of subsequently sent
+    /// packets is flipped. The keys of the previous key phase are kept around
+    /// to process packets sent by the peer before it notices the update.
+    ///
+    /// Returns [`InvalidState`] if the handshake is not complete yet, or if
+    /// no packet protected with the keys of the previous key update has been
+    /// received from the peer yet.
+    ///
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn initiate_key_update(&mut self) -> Result<()> {
+        if !self.handshake_completed || self.application.key_update_pending {
+            return Err(Error::InvalidState);
+        }
+
+        let keys = match self.application.secrets {
+            Some(ref v) => v.next()?,
+
+            None => return Err(Error::InvalidState),
+        };
+
+        trace!("{} key update initiated", self.trace_id);
+
+        self.application.update_keys(keys);
+        self.application.key_update_pending = true;
+
+        Ok(())
+    }
+
     /// Returns a string uniquely representing the connection.
     ///
     /// This can be used for logging purposes to differentiate between multiple
@@ -1692,6 +1988,34 @@ impl Connection {
         &self.trace_id
     }
 
+    /// Returns the idle timeout in effect on the connection.
+    ///
+    /// This is the minimum of the `idle_timeout` transport parameters sent by
+    /// each endpoint, ignoring the ones that are zero. A zero duration means
+    /// that the connection never times out for inactivity. Before the peer's
+    /// transport parameters have been received, only the local value is
+    /// taken into account.
+    pub fn effective_idle_timeout(&self) -> time::Duration {
+        let local = self.local_transport_params.idle_timeout;
+        let peer = self.peer_transport_params.idle_timeout;
+
+        let secs = match (local, peer) {
+            (0, v) | (v, 0) => v,
+
+            (local, peer) => cmp::min(local, peer),
+        };
+
+        time::Duration::from_secs(secs)
+    }
+
+    /// Returns the QUIC version used by the connection.
+    ///
+    /// On the client this reflects the outcome of version negotiation, if it
+    /// happened.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
     /// Returns the negotiated ALPN protocol.
     ///
     /// If no protocol has been negotiated, the returned value is empty.
@@ -1699,6 +2023,25 @@ impl Connection {
         self.tls_state.get_alpn_protocol()
     }
 
+    /// Returns the certificate chain presented by the peer.
+    ///
+    /// Each certificate is DER-encoded, starting with the peer's leaf
+    /// certificate followed by any intermediates. The returned list is empty
+    /// if the handshake hasn't progressed far enough, or if the peer didn't
+    /// present any certificate (e.g. a client when the server doesn't request
+    /// one).
+    pub fn peer_cert_chain(&self) -> Vec<Vec<u8>> {
+        self.tls_state.get_peer_cert_chain()
+    }
+
+    /// Returns the time elapsed since the connection was created.
+    ///
+    /// This can be used to enforce a maximum connection lifetime, independently
+    /// of the idle timeout.
+    pub fn time_since_creation(&self) -> time::Duration {
+        self.created.elapsed()
+    }
+
     /// Returns true if the connection handshake is complete.
     pub fn is_established(&self) -> bool {
         self.handshake_completed
@@ -1899,7 +2242,8 @@ impl TransportParams {
 
         if !is_server {
             // Ignore supported versions from server.
-            b.get_bytes_with_u8_length()?;
+            let len = b.get_u8()?;
+            b.skip(usize::from(len))?;
         }
 
         let mut tp = TransportParams::default();
@@ -2127,6 +2471,108 @@ impl std::fmt::Debug for TransportParams {
 }
 
 
+/// Helpers for driving a client and a server connection against each other
+/// in tests, without the need for real sockets.
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+
+    /// An in-memory pipe between a client and a server connection.
+    pub struct Pipe {
+        pub client: Box<Connection>,
+        pub server: Box<Connection>,
+    }
+
+    impl Pipe {
+        /// Creates a client and a server connection sharing a default
+        /// configuration suitable for tests.
+        pub fn new() -> Result<Pipe> {
+            let mut config = Config::new(VERSION_DRAFT17)?;
+            config.load_cert_chain_from_pem_file("examples/cert.crt")?;
+            config.load_priv_key_from_pem_file("examples/cert.key")?;
+            config.set_application_protos(&[b"proto1", b"proto2"])?;
+            config.set_initial_max_data(1000);
+            config.set_initial_max_stream_data_bidi_local(500);
+            config.set_initial_max_stream_data_bidi_remote(500);
+            config.set_initial_max_streams_bidi(3);
+            config.set_initial_max_streams_uni(3);
+            config.verify_peer(false);
+
+            Pipe::with_config(&mut config)
+        }
+
+        /// Creates a client and a server connection from the given
+        /// configuration.
+        pub fn with_config(config: &mut Config) -> Result<Pipe> {
+            let mut client_scid: [u8; 16] = [0; 16];
+            rand::rand_bytes(&mut client_scid[..]);
+
+            let mut server_scid: [u8; 16] = [0; 16];
+            rand::rand_bytes(&mut server_scid[..]);
+
+            Ok(Pipe {
+                client: connect(Some("quic.tech"), &client_scid, config)?,
+                server: accept(&server_scid, None, config)?,
+            })
+        }
+
+        /// Exchanges packets until the handshake is complete on both sides.
+        pub fn handshake(&mut self, buf: &mut [u8]) -> Result<()> {
+            self.advance(buf)?;
+
+            if !self.client.is_established() || !self.server.is_established() {
+                return Err(Error::InvalidState);
+            }
+
+            Ok(())
+        }
+
+        /// Exchanges packets until neither side has anything left to send.
+        pub fn advance(&mut self, buf: &mut [u8]) -> Result<()> {
+            loop {
+                let client_sent = flush(&mut self.client, &mut self.server, buf)?;
+                let server_sent = flush(&mut self.server, &mut self.client, buf)?;
+
+                if !client_sent && !server_sent {
+                    break;
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    /// Delivers all the packets `from` has to send to `to`, one at a time.
+    ///
+    /// Returns whether any packet was sent.
+    fn flush(from: &mut Connection, to: &mut Connection, buf: &mut [u8])
+                                                            -> Result<bool> {
+        let mut sent = false;
+
+        loop {
+            let len = match from.send(buf) {
+                Ok(v) => v,
+
+                Err(Error::Done) => break,
+
+                Err(e) => return Err(e),
+            };
+
+            match to.recv(&mut buf[..len]) {
+                Ok(_) => (),
+
+                Err(Error::Done) => (),
+
+                Err(e) => return Err(e),
+            }
+
+            sent = true;
+        }
+
+        Ok(sent)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -2160,6 +2606,38 @@ mod tests {
         assert_eq!(new_tp, tp);
     }
 
+    #[test]
+    fn ack_delay_transport_params() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(VERSION_DRAFT17).unwrap();
+        config.load_cert_chain_from_pem_file("examples/cert.crt").unwrap();
+        config.load_priv_key_from_pem_file("examples/cert.key").unwrap();
+        config.set_application_protos(&[b"proto1"]).unwrap();
+        config.set_ack_delay_exponent(5);
+        config.set_max_ack_delay(40);
+        config.verify_peer(false);
+
+        let mut raw_params: [u8; 256] = [42; 256];
+        let mut raw_params =
+            TransportParams::encode(&config.local_transport_params,
+                                    VERSION_DRAFT17, true,
+                                    &mut raw_params).unwrap();
+
+        let tp = TransportParams::decode(&mut raw_params, VERSION_DRAFT17,
+                                         false).unwrap();
+        assert_eq!(tp.ack_delay_exponent, 5);
+        assert_eq!(tp.max_ack_delay, 40);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        assert_eq!(pipe.client.peer_transport_params.ack_delay_exponent, 5);
+        assert_eq!(pipe.client.peer_transport_params.max_ack_delay, 40);
+        assert_eq!(pipe.server.peer_transport_params.ack_delay_exponent, 5);
+        assert_eq!(pipe.server.peer_transport_params.max_ack_delay, 40);
+    }
+
     fn create_conn(is_server: bool) -> Box<Connection> {
         let mut scid: [u8; 16] = [0; 16];
         rand::rand_bytes(&mut scid[..]);
@@ -2214,6 +2692,442 @@ mod tests {
 
         assert!(true);
     }
+
+    #[test]
+    fn pipe_request_response() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(4, b"GET /index.html\r\n", true),
+                   Ok(17));
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+
+        let readable: Vec<u64> = pipe.server.readable().collect();
+        assert_eq!(readable, vec![4]);
+
+        let mut out = [0; 128];
+
+        assert_eq!(pipe.server.stream_recv(4, &mut out), Ok((17, true)));
+        assert_eq!(&out[..17], b"GET /index.html\r\n");
+
+        assert_eq!(pipe.server.stream_send(4, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+
+        assert_eq!(pipe.client.stream_recv(4, &mut out), Ok((5, true)));
+        assert_eq!(&out[..5], b"hello");
+    }
+
+    #[test]
+    fn stream_send_beyond_flow_control_window() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(4, b"GET /index.html\r\n", true),
+                   Ok(17));
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+
+        let mut out = [0; 1000];
+        assert_eq!(pipe.server.stream_recv(4, &mut out), Ok((17, true)));
+
+        // The response is larger than the stream's initial window, but it's
+        // buffered in full and delivered as the client grants more credit.
+        let resp = [0xba; 900];
+        assert_eq!(pipe.server.stream_send(4, &resp, true), Ok(900));
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+
+        assert_eq!(pipe.client.stream_recv(4, &mut out), Ok((500, false)));
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+
+        assert_eq!(pipe.client.stream_recv(4, &mut out), Ok((400, true)));
+        assert_eq!(&out[..400], &resp[..400]);
+    }
+
+    #[test]
+    fn peer_streams_left_bidi() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.client.peer_streams_left_bidi(), 0);
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+        assert_eq!(pipe.client.peer_streams_left_bidi(), 3);
+        assert_eq!(pipe.server.peer_streams_left_bidi(), 3);
+
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+        assert_eq!(pipe.client.peer_streams_left_bidi(), 2);
+
+        // Writing on an already open stream doesn't use up the budget.
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+        assert_eq!(pipe.client.peer_streams_left_bidi(), 2);
+
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
+        assert_eq!(pipe.client.peer_streams_left_bidi(), 0);
+
+        assert_eq!(pipe.client.stream_send(12, b"a", false),
+                   Err(Error::StreamLimit));
+        assert_eq!(pipe.client.peer_streams_left_uni(), 3);
+    }
+
+    #[test]
+    fn peer_streams_left_bidi_out_of_order() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+        assert_eq!(pipe.client.peer_streams_left_bidi(), 3);
+
+        // Opening stream 8 implicitly opens streams 0 and 4 as well.
+        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
+        assert_eq!(pipe.client.peer_streams_left_bidi(), 0);
+
+        assert_eq!(pipe.client.stream_send(12, b"a", false),
+                   Err(Error::StreamLimit));
+        assert_eq!(pipe.client.stream_send(1 << 36, b"a", false),
+                   Err(Error::StreamLimit));
+
+        // Lower-numbered streams are still within the limit.
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+        assert_eq!(pipe.client.peer_streams_left_bidi(), 0);
+    }
+
+    #[test]
+    fn max_idle_timeout() {
+        let mut config = Config::new(VERSION_DRAFT17).unwrap();
+
+        assert_eq!(config.set_max_idle_timeout(time::Duration::from_secs(30)),
+                   Ok(()));
+        assert_eq!(config.local_transport_params.idle_timeout, 30);
+
+        assert_eq!(config.set_max_idle_timeout(time::Duration::from_millis(1500)),
+                   Ok(()));
+        assert_eq!(config.local_transport_params.idle_timeout, 2);
+
+        assert_eq!(config.set_max_idle_timeout(time::Duration::from_secs(0)),
+                   Ok(()));
+        assert_eq!(config.local_transport_params.idle_timeout, 0);
+
+        assert_eq!(config.set_max_idle_timeout(
+                       time::Duration::from_secs(std::u64::MAX)),
+                   Err(Error::InvalidTransportParam));
+        assert_eq!(config.local_transport_params.idle_timeout, 0);
+    }
+
+    #[test]
+    fn session_ticket_key() {
+        let mut config = Config::new(VERSION_DRAFT17).unwrap();
+
+        assert_eq!(config.set_session_ticket_key(&[0xba; 48]), Ok(()));
+
+        // Rotating the key.
+        assert_eq!(config.set_session_ticket_key(&[0xab; 48]), Ok(()));
+
+        // Keys of the wrong length are rejected through the C API.
+        let key = [0xba; 32];
+        assert_eq!(ffi::quiche_config_set_session_ticket_key(&mut config,
+                                                             key.as_ptr(),
+                                                             key.len()),
+                   Error::TlsFail.to_c() as libc::c_int);
+    }
+
+    #[test]
+    fn version() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        assert_eq!(pipe.client.version(), VERSION_DRAFT17);
+        assert_eq!(pipe.server.version(), VERSION_DRAFT17);
+    }
+
+    #[test]
+    fn effective_idle_timeout() {
+        let mut pipe = testing::Pipe::new().unwrap();
+
+        assert_eq!(pipe.client.effective_idle_timeout(),
+                   time::Duration::from_secs(0));
+
+        pipe.client.local_transport_params.idle_timeout = 30;
+        assert_eq!(pipe.client.effective_idle_timeout(),
+                   time::Duration::from_secs(30));
+
+        pipe.client.peer_transport_params.idle_timeout = 10;
+        assert_eq!(pipe.client.effective_idle_timeout(),
+                   time::Duration::from_secs(10));
+
+        pipe.client.local_transport_params.idle_timeout = 0;
+        assert_eq!(pipe.client.effective_idle_timeout(),
+                   time::Duration::from_secs(10));
+    }
+
+    #[test]
+    fn idle_timeout_after_handshake() {
+        let mut buf = [0; 65535];
+
+        let mut client_config = Config::new(VERSION_DRAFT17).unwrap();
+        client_config.set_application_protos(&[b"proto1"]).unwrap();
+        client_config.set_max_idle_timeout(time::Duration::from_secs(30))
+                     .unwrap();
+        client_config.verify_peer(false);
+
+        let mut server_config = Config::new(VERSION_DRAFT17).unwrap();
+        server_config.load_cert_chain_from_pem_file("examples/cert.crt")
+                     .unwrap();
+        server_config.load_priv_key_from_pem_file("examples/cert.key")
+                     .unwrap();
+        server_config.set_application_protos(&[b"proto1"]).unwrap();
+        server_config.set_max_idle_timeout(time::Duration::from_secs(10))
+                     .unwrap();
+
+        let mut pipe = testing::Pipe {
+            client: connect(Some("quic.tech"), &[0xba; 16],
+                            &mut client_config).unwrap(),
+            server: accept(&[0xab; 16], None, &mut server_config).unwrap(),
+        };
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        // Both sides use the server's shorter timeout.
+        let timeout = pipe.client.timeout().unwrap();
+        assert!(timeout <= time::Duration::from_secs(10));
+        assert!(timeout > time::Duration::from_secs(9));
+
+        let timeout = pipe.server.timeout().unwrap();
+        assert!(timeout <= time::Duration::from_secs(10));
+        assert!(timeout > time::Duration::from_secs(9));
+    }
+
+    #[test]
+    fn no_idle_timeout() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        assert_eq!(pipe.client.timeout(), None);
+        assert_eq!(pipe.server.timeout(), None);
+    }
+
+    #[test]
+    fn trace_id() {
+        let mut config = Config::new(VERSION_DRAFT17).unwrap();
+        config.load_cert_chain_from_pem_file("examples/cert.crt").unwrap();
+        config.load_priv_key_from_pem_file("examples/cert.key").unwrap();
+        config.verify_peer(false);
+
+        let pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_ne!(pipe.client.trace_id(), pipe.server.trace_id());
+
+        config.set_trace_id("test");
+
+        let pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.client.trace_id(), "test");
+        assert_eq!(pipe.server.trace_id(), "test");
+    }
+
+    #[test]
+    fn has_pending_send() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert!(pipe.client.has_pending_send());
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+        assert!(!pipe.client.has_pending_send());
+        assert!(!pipe.server.has_pending_send());
+
+        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
+        assert!(pipe.client.has_pending_send());
+
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+        assert!(!pipe.client.has_pending_send());
+        assert!(!pipe.server.has_pending_send());
+
+        assert_eq!(pipe.client.close(false, 0x0, b""), Ok(()));
+        assert!(pipe.client.has_pending_send());
+
+        assert!(pipe.client.send(&mut buf).is_ok());
+        assert!(!pipe.client.has_pending_send());
+    }
+
+    #[test]
+    fn odcid_without_retry_token() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(VERSION_DRAFT17).unwrap();
+        config.load_cert_chain_from_pem_file("examples/cert.crt").unwrap();
+        config.load_priv_key_from_pem_file("examples/cert.key").unwrap();
+        config.set_application_protos(&[b"proto1"]).unwrap();
+        config.verify_peer(false);
+
+        let client_scid = [0xba; 16];
+        let server_scid = [0xab; 16];
+
+        let mut client =
+            connect(Some("quic.tech"), &client_scid, &mut config).unwrap();
+        let mut server =
+            accept(&server_scid, Some(&[0xcd; 16]), &mut config).unwrap();
+
+        // The client didn't go through a retry, so its Initial has no token.
+        let len = client.send(&mut buf).unwrap();
+        assert_eq!(server.recv(&mut buf[..len]), Err(Error::InvalidPacket));
+    }
+
+    #[test]
+    fn key_update() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.client.initiate_key_update(), Err(Error::InvalidState));
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        assert_eq!(pipe.client.initiate_key_update(), Ok(()));
+        assert_eq!(pipe.client.initiate_key_update(), Err(Error::InvalidState));
+
+        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
+        assert_eq!(&b[..5], b"hello");
+
+        assert!(pipe.server.application.key_phase);
+        assert!(pipe.client.application.key_phase);
+
+        // The server has responded using the new keys, so a new update can
+        // start.
+        assert_eq!(pipe.client.initiate_key_update(), Ok(()));
+    }
+
+    #[test]
+    fn key_update_by_server() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        assert_eq!(pipe.server.initiate_key_update(), Ok(()));
+
+        assert_eq!(pipe.server.stream_send(1, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+
+        // The client followed the server's update.
+        let mut b = [0; 15];
+        assert_eq!(pipe.client.stream_recv(1, &mut b), Ok((5, true)));
+        assert_eq!(&b[..5], b"hello");
+
+        assert!(pipe.client.application.key_phase);
+        assert!(pipe.server.application.key_phase);
+
+        assert_eq!(pipe.client.stream_send(1, b"world", true), Ok(5));
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+
+        assert_eq!(pipe.server.stream_recv(1, &mut b), Ok((5, true)));
+        assert_eq!(&b[..5], b"world");
+
+        assert_eq!(pipe.server.initiate_key_update(), Ok(()));
+    }
+
+    #[test]
+    fn key_update_reordered() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        // Packet protected with the keys of the initial key phase.
+        assert_eq!(pipe.client.stream_send(4, b"hello", false), Ok(5));
+        let len = pipe.client.send(&mut buf).unwrap();
+        let mut old = buf[..len].to_vec();
+
+        assert_eq!(pipe.client.initiate_key_update(), Ok(()));
+
+        // Packet protected with the new keys.
+        assert_eq!(pipe.client.stream_send(4, b"world", true), Ok(5));
+        let len = pipe.client.send(&mut buf).unwrap();
+        let mut new = buf[..len].to_vec();
+
+        // The server switches to the new keys, and then receives the older
+        // packet, which is decrypted with the keys of the previous phase.
+        assert_eq!(pipe.server.recv(&mut new), Ok(new.len()));
+        assert!(pipe.server.application.key_phase);
+
+        assert_eq!(pipe.server.recv(&mut old), Ok(old.len()));
+        assert!(pipe.server.application.key_phase);
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((10, true)));
+        assert_eq!(&b[..10], b"helloworld");
+    }
+
+    #[test]
+    fn virtual_host() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(VERSION_DRAFT17).unwrap();
+        config.load_cert_chain_from_pem_file("examples/cert.crt").unwrap();
+        config.load_priv_key_from_pem_file("examples/cert.key").unwrap();
+        config.add_virtual_host("QUIC.tech", "examples/cert.crt",
+                                "examples/cert.key").unwrap();
+        config.set_application_protos(&[b"proto1"]).unwrap();
+        config.verify_peer(false);
+
+        assert!(config.add_virtual_host("quic.tech", "missing.crt",
+                                        "examples/cert.key").is_err());
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        // ALPN still works after switching to the virtual host's context.
+        assert_eq!(pipe.client.application_proto(), b"proto1");
+        assert_eq!(pipe.client.peer_cert_chain().len(), 1);
+    }
+
+    #[test]
+    fn virtual_host_config_dropped() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = {
+            let mut config = Config::new(VERSION_DRAFT17).unwrap();
+            config.load_cert_chain_from_pem_file("examples/cert.crt").unwrap();
+            config.load_priv_key_from_pem_file("examples/cert.key").unwrap();
+            config.add_virtual_host("quic.tech", "examples/cert.crt",
+                                    "examples/cert.key").unwrap();
+            config.set_application_protos(&[b"proto1"]).unwrap();
+            config.verify_peer(false);
+
+            testing::Pipe::with_config(&mut config).unwrap()
+        };
+
+        // The configuration is gone by the time the server processes the
+        // ClientHello and looks up the virtual host.
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        assert_eq!(pipe.client.application_proto(), b"proto1");
+        assert_eq!(pipe.client.peer_cert_chain().len(), 1);
+    }
+
+    #[test]
+    fn peer_cert_chain() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::new().unwrap();
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        // The server presents its single self-signed certificate.
+        let chain = pipe.client.peer_cert_chain();
+        assert_eq!(chain.len(), 1);
+        assert_eq!(chain[0][0], 0x30);
+
+        // The server doesn't request a client certificate.
+        assert!(pipe.server.peer_cert_chain().is_empty());
+    }
 }
 
 pub use crate::stream::Readable;
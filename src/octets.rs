@@ -33,6 +33,9 @@ use std::ptr;
 use crate::Result;
 use crate::Error;
 
+/// The largest value that can be encoded as a variable-length integer.
+pub const MAX_VAR_INT: u64 = 4_611_686_018_427_387_903;
+
 macro_rules! peek_u {
     ($b:expr, $ty:ty, $len:expr) => ({
         let src = &$b.buf[$b.off..];
@@ -221,7 +224,7 @@ impl<'a> Octets<'a> {
         } else if v <= 1_073_741_823 {
             let buf = self.put_u32(v as u32)?;
             buf[0] |= 0x80;
-        } else if v <= 4_611_686_018_427_387_903 {
+        } else if v <= MAX_VAR_INT {
             let buf = self.put_u64(v)?;
             buf[0] |= 0xc0;
         } else {
@@ -271,6 +274,17 @@ impl<'a> Octets<'a> {
         self.get_bytes(len as usize)
     }
 
+    /// Advances the buffer by `len` bytes without reading them.
+    pub fn skip(&mut self, len: usize) -> Result<()> {
+        if self.cap() < len {
+            return Err(Error::BufferTooShort)
+        }
+
+        self.off += len;
+
+        Ok(())
+    }
+
     /// Reads `len` bytes from the current offset without copying and without
     /// advancing the buffer.
     pub fn peek_bytes(&mut self, len: usize) -> Result<Octets> {
@@ -501,6 +515,31 @@ mod tests {
         b.get_bytes(5).unwrap();
     }
 
+    #[test]
+    fn skip() {
+        let mut d: [u8; 10] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        let mut b = Octets::with_slice(&mut d);
+        assert_eq!(b.cap(), 10);
+        assert_eq!(b.off(), 0);
+
+        assert!(b.skip(5).is_ok());
+        assert_eq!(b.cap(), 5);
+        assert_eq!(b.off(), 5);
+
+        assert_eq!(b.skip(6), Err(Error::BufferTooShort));
+        assert_eq!(b.cap(), 5);
+        assert_eq!(b.off(), 5);
+
+        assert_eq!(b.get_u8().unwrap(), 6);
+
+        assert!(b.skip(4).is_ok());
+        assert_eq!(b.cap(), 0);
+        assert_eq!(b.off(), 10);
+
+        assert!(b.skip(0).is_ok());
+        assert!(b.skip(1).is_err());
+    }
+
     #[test]
     fn get_varint() {
         let mut d: [u8; 8] = [0xc2, 0x19, 0x7c, 0x5e, 0xff, 0x14, 0xe8, 0x8c];
@@ -596,6 +596,16 @@ pub struct PktNumSpace {
     pub crypto_seal: Option<crypto::Seal>,
 
     pub crypto_stream: stream::Stream,
+
+    pub secrets: Option<crypto::Secrets>,
+
+    pub key_phase: bool,
+
+    pub key_update_pending: bool,
+
+    pub key_phase_start_pkt_num: u64,
+
+    pub prev_crypto_open: Option<crypto::Open>,
 }
 
 impl PktNumSpace {
@@ -621,6 +631,16 @@ impl PktNumSpace {
             crypto_seal: None,
 
             crypto_stream: stream::Stream::new(std::usize::MAX, std::usize::MAX),
+
+            secrets: None,
+
+            key_phase: false,
+
+            key_update_pending: false,
+
+            key_phase_start_pkt_num: 0,
+
+            prev_crypto_open: None,
         }
     }
 
@@ -634,6 +654,18 @@ impl PktNumSpace {
         self.crypto_seal.as_ref().unwrap().alg().tag_len()
     }
 
+    /// Switches to the keys of the next key phase.
+    pub fn update_keys(&mut self, keys: (crypto::Open, crypto::Seal,
+                                         crypto::Secrets)) {
+        let (open, seal, secrets) = keys;
+
+        self.prev_crypto_open = self.crypto_open.replace(open);
+        self.crypto_seal = Some(seal);
+        self.secrets = Some(secrets);
+
+        self.key_phase = !self.key_phase;
+    }
+
     pub fn ready(&self) -> bool {
         self.crypto_stream.writable() || !self.flight.lost.is_empty() || self.do_ack
     }
@@ -106,6 +106,10 @@ impl Stream {
         self.recv.ready()
     }
 
+    pub fn is_fin(&self) -> bool {
+        self.recv.is_fin()
+    }
+
     pub fn writable(&self) -> bool {
         self.send.ready() && self.send.off() <= self.max_tx_data
     }
@@ -158,10 +162,17 @@ struct RecvBuf {
     data: BinaryHeap<RangeBuf>,
     off: usize,
     len: usize,
+    fin_off: Option<usize>,
 }
 
 impl RecvBuf {
     fn push(&mut self, buf: RangeBuf) -> Result<()> {
+        // Remember the final size of the stream, even if the buffer itself
+        // turns out to be a duplicate.
+        if buf.fin() {
+            self.fin_off = Some(buf.max_off());
+        }
+
         // TODO: discard duplicated data (e.g. using RangeSet)
         if self.off >= buf.off() + buf.len() {
             // Data is fully duplicate.
@@ -225,6 +236,10 @@ impl RecvBuf {
         buf.off == self.off
     }
 
+    fn is_fin(&self) -> bool {
+        self.fin_off == Some(self.off)
+    }
+
     #[allow(dead_code)]
     fn off(&self) -> usize {
         self.off
@@ -536,6 +551,46 @@ mod tests {
         assert_eq!(recv.off(), 19);
     }
 
+    #[test]
+    fn fin_read() {
+        let mut stream = Stream::new(15, 0);
+        assert!(!stream.is_fin());
+
+        let mut buf = vec![0; 32];
+
+        let first = RangeBuf::from(b"hello", 0, false);
+        let second = RangeBuf::from(b"world", 5, true);
+
+        assert!(stream.recv_push(first).is_ok());
+        assert!(!stream.is_fin());
+
+        assert_eq!(stream.recv_pop(&mut buf), Ok((5, false)));
+        assert!(!stream.is_fin());
+
+        assert!(stream.recv_push(second).is_ok());
+        assert!(!stream.is_fin());
+
+        assert_eq!(stream.recv_pop(&mut buf), Ok((5, true)));
+        assert!(stream.is_fin());
+    }
+
+    #[test]
+    fn zero_len_fin_read() {
+        let mut stream = Stream::new(15, 0);
+
+        let mut buf = vec![0; 32];
+
+        let first = RangeBuf::from(b"hello", 0, false);
+        let second = RangeBuf::from(b"", 5, true);
+
+        assert!(stream.recv_push(first).is_ok());
+        assert_eq!(stream.recv_pop(&mut buf), Ok((5, false)));
+        assert!(!stream.is_fin());
+
+        assert!(stream.recv_push(second).is_ok());
+        assert!(stream.is_fin());
+    }
+
     #[test]
     fn zero_len_read() {
         let mut recv = RecvBuf::default();
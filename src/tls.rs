@@ -27,10 +27,13 @@
 
 use std::ffi;
 use std::ptr;
+use std::rc;
 use std::slice;
 
 use std::io::prelude::*;
 
+use std::collections::HashMap;
+
 use libc::c_char;
 use libc::c_int;
 use libc::c_uint;
@@ -78,6 +81,14 @@ struct SSL_CIPHER(c_void);
 #[repr(transparent)]
 struct X509_VERIFY_PARAM(c_void);
 
+#[allow(non_camel_case_types)]
+#[repr(transparent)]
+struct X509(c_void);
+
+#[allow(non_camel_case_types)]
+#[repr(transparent)]
+struct STACK(c_void);
+
 #[repr(C)]
 #[allow(non_camel_case_types)]
 struct SSL_QUIC_METHOD {
@@ -107,7 +118,19 @@ static QUICHE_STREAM_METHOD: SSL_QUIC_METHOD = SSL_QUIC_METHOD {
     send_alert,
 };
 
-pub struct Context(*mut SSL_CTX);
+/// Contexts to switch to based on the client's SNI, keyed by lowercase server
+/// name.
+pub type VirtualHosts = HashMap<String, rc::Rc<Context>>;
+
+pub struct Context {
+    ctx: *mut SSL_CTX,
+
+    // Connections hold a reference to this, so that the contexts outlive the
+    // configuration they were added to.
+    vhosts: rc::Rc<VirtualHosts>,
+
+    keylog: bool,
+}
 
 impl Context {
     #[allow(clippy::new_ret_no_self)]
@@ -117,7 +140,11 @@ impl Context {
 
             map_result(SSL_CTX_set_default_verify_paths(ctx))?;
 
-            Ok(Context(ctx))
+            Ok(Context {
+                ctx,
+                vhosts: rc::Rc::new(HashMap::new()),
+                keylog: false,
+            })
         }
     }
 
@@ -158,6 +185,62 @@ impl Context {
         unsafe {
             SSL_CTX_set_keylog_callback(self.as_ptr(), keylog);
         }
+
+        // The keylog callback is looked up on the context the connection
+        // switched to, so virtual hosts need it as well.
+        for vhost in self.vhosts.values() {
+            unsafe {
+                SSL_CTX_set_keylog_callback(vhost.as_ptr(), keylog);
+            }
+        }
+
+        self.keylog = true;
+    }
+
+    pub fn add_vhost(&mut self, name: &str, cert: &str, key: &str)
+                                                            -> Result<()> {
+        let mut vhost = Context::new()?;
+
+        vhost.use_certificate_chain_file(cert)?;
+        vhost.use_privkey_file(key)?;
+
+        // ALPN is negotiated after the SNI callback has run, so the virtual
+        // host needs the selection callback too. The list of protocols is
+        // taken from the connection itself.
+        unsafe {
+            SSL_CTX_set_alpn_select_cb(vhost.as_ptr(), select_alpn,
+                                       ptr::null_mut());
+        }
+
+        if self.keylog {
+            vhost.enable_keylog();
+        }
+
+        // Existing connections keep using the table they were created with,
+        // so it's copied rather than modified in place.
+        let mut vhosts = (*self.vhosts).clone();
+        vhosts.insert(name.to_ascii_lowercase(), rc::Rc::new(vhost));
+
+        self.vhosts = rc::Rc::new(vhosts);
+
+        unsafe {
+            SSL_CTX_set_tlsext_servername_callback(self.as_ptr(),
+                                                   select_vhost);
+        }
+
+        Ok(())
+    }
+
+    pub fn vhosts(&self) -> rc::Rc<VirtualHosts> {
+        self.vhosts.clone()
+    }
+
+    pub fn set_ticket_key(&mut self, key: &[u8]) -> Result<()> {
+        map_result(unsafe {
+            SSL_CTX_set_tlsext_ticket_keys(self.as_ptr(),
+                                           key.as_ptr() as *const c_void,
+                                           key.len())
+        })
     }
 
     pub fn set_alpn(&mut self, v: &[Vec<u8>]) -> Result<()> {
@@ -181,7 +264,7 @@ impl Context {
     }
 
     fn as_ptr(&self) -> *mut SSL_CTX {
-        self.0
+        self.ctx
     }
 }
 
@@ -323,6 +406,47 @@ impl Handshake {
         unsafe { slice::from_raw_parts(ptr, len as usize) }
     }
 
+    pub fn get_peer_cert_chain(&self) -> Vec<Vec<u8>> {
+        let mut chain = Vec::new();
+
+        let certs = unsafe {
+            SSL_get_peer_full_cert_chain(self.as_ptr())
+        };
+
+        if certs.is_null() {
+            return chain;
+        }
+
+        let num = unsafe { sk_num(certs) };
+
+        for i in 0..num {
+            let cert = unsafe { sk_value(certs, i) as *const X509 };
+
+            // Calling i2d_X509() without an output buffer only returns the
+            // length of the encoded certificate.
+            let len = unsafe { i2d_X509(cert, ptr::null_mut()) };
+
+            if len <= 0 {
+                continue;
+            }
+
+            let mut der = vec![0; len as usize];
+            let mut out = der.as_mut_ptr();
+
+            let len = unsafe { i2d_X509(cert, &mut out) };
+
+            if len <= 0 {
+                continue;
+            }
+
+            der.truncate(len as usize);
+
+            chain.push(der);
+        }
+
+        chain
+    }
+
     pub fn provide_data(&self, level: crypto::Level, buf: &[u8]) -> Result<()> {
         map_result_ssl(self, unsafe {
             SSL_provide_quic_data(self.as_ptr(), level, buf.as_ptr(), buf.len())
@@ -446,6 +570,9 @@ extern fn set_encryption_secrets(ssl: *mut SSL, level: crypto::Level,
 
     space.crypto_open = Some(open);
 
+    let read_hp_key = pn_key.clone();
+    let read_secret_buf = secret;
+
     let secret = unsafe { slice::from_raw_parts(write_secret, secret_len) };
 
     if crypto::derive_pkt_key(aead, &secret, &mut key).is_err() {
@@ -467,6 +594,13 @@ extern fn set_encryption_secrets(ssl: *mut SSL, level: crypto::Level,
 
     space.crypto_seal = Some(seal);
 
+    // Keep the 1-RTT secrets around, as they are needed for key updates.
+    if level == crypto::Level::Application {
+        space.secrets = Some(crypto::Secrets::new(aead, &read_secret_buf,
+                                                  &read_hp_key, &secret,
+                                                  &pn_key));
+    }
+
     1
 }
 
@@ -517,19 +651,32 @@ extern fn send_alert(ssl: *mut SSL, level: crypto::Level, alert: u8) -> c_int {
     1
 }
 
-extern fn keylog(_: *mut SSL, line: *const c_char) {
+extern fn keylog(ssl: *mut SSL, line: *const c_char) {
+    let data = unsafe {
+        ffi::CStr::from_ptr(line).to_bytes()
+    };
+
+    let mut full_line = Vec::with_capacity(data.len() + 6);
+    full_line.extend_from_slice(b"QUIC_");
+    full_line.extend_from_slice(data);
+    full_line.push(b'\n');
+
+    // Prefer the file configured on the connection, if any.
+    let conn = get_ex_data_from_ptr::<Connection>(ssl, *QUICHE_EX_DATA_INDEX);
+
+    if let Some(file) = conn.and_then(|c| c.keylog.as_ref()) {
+        // Write the whole line at once, as the file might be shared by
+        // multiple connections.
+        (&**file).write_all(&full_line).unwrap_or(());
+        return;
+    }
+
     if let Some(path) = std::env::var_os("SSLKEYLOGFILE") {
         let file = std::fs::OpenOptions::new().create(true)
                                               .append(true)
                                               .open(path);
         if let Ok(mut file) = file {
-            let data = unsafe {
-                ffi::CStr::from_ptr(line).to_bytes()
-            };
-
-            file.write_all(b"QUIC_").unwrap_or(());
-            file.write_all(data).unwrap_or(());
-            file.write_all(b"\n").unwrap_or(());
+            file.write_all(&full_line).unwrap_or(());
         }
     }
 }
@@ -575,6 +722,35 @@ extern fn select_alpn(ssl: *mut SSL, out: *mut *const u8, out_len: *mut u8,
     3 // SSL_TLSEXT_ERR_NOACK
 }
 
+extern fn select_vhost(ssl: *mut SSL, _out_alert: *mut c_int,
+                        _arg: *mut c_void) -> c_int {
+    let conn = match get_ex_data_from_ptr::<Connection>(ssl, *QUICHE_EX_DATA_INDEX) {
+        Some(v) => v,
+        None    => return 0, // SSL_TLSEXT_ERR_OK
+    };
+
+    // TLSEXT_NAMETYPE_host_name
+    let name = unsafe { SSL_get_servername(ssl, 0) };
+
+    // Keep using the default certificate if the client didn't send SNI.
+    if name.is_null() {
+        return 0; // SSL_TLSEXT_ERR_OK
+    }
+
+    let name = match unsafe { ffi::CStr::from_ptr(name) }.to_str() {
+        Ok(v)  => v.to_ascii_lowercase(),
+        Err(_) => return 0, // SSL_TLSEXT_ERR_OK
+    };
+
+    if let Some(vhost) = conn.vhosts.get(&name) {
+        unsafe {
+            SSL_set_SSL_CTX(ssl, vhost.as_ptr());
+        }
+    }
+
+    0 // SSL_TLSEXT_ERR_OK
+}
+
 fn map_result(bssl_result: c_int) -> Result<()> {
     match bssl_result {
         1 => Ok(()),
@@ -677,6 +853,13 @@ extern {
                       inp: *mut u8, in_len: libc::c_uint, arg: *mut c_void)
                       -> c_int, arg: *mut c_void);
 
+    fn SSL_CTX_set_tlsext_servername_callback(ctx: *mut SSL_CTX,
+        cb: extern fn(ssl: *mut SSL, out_alert: *mut c_int, arg: *mut c_void)
+                      -> c_int) -> c_int;
+
+    fn SSL_CTX_set_tlsext_ticket_keys(ctx: *mut SSL_CTX, keys: *const c_void,
+                                      keys_len: usize) -> c_int;
+
     // SSL
     fn SSL_get_ex_new_index(argl: libc::c_long, argp: *const c_void,
         unused: *const c_void, dup_unused: *const c_void,
@@ -703,6 +886,10 @@ extern {
 
     fn SSL_set_tlsext_host_name(ssl: *mut SSL, name: *const c_char) -> c_int;
 
+    fn SSL_get_servername(ssl: *mut SSL, ty: c_int) -> *const c_char;
+
+    fn SSL_set_SSL_CTX(ssl: *mut SSL, ctx: *mut SSL_CTX) -> *mut SSL_CTX;
+
     fn SSL_set_quic_transport_params(ssl: *mut SSL, params: *const u8,
         params_len: usize) -> c_int;
 
@@ -715,6 +902,8 @@ extern {
     fn SSL_get0_alpn_selected(ssl: *mut SSL,
         out: *mut *const u8, out_len: *mut u32);
 
+    fn SSL_get_peer_full_cert_chain(ssl: *mut SSL) -> *const STACK;
+
     fn SSL_provide_quic_data(ssl: *mut SSL, level: crypto::Level,
         data: *const u8, len: usize) -> c_int;
 
@@ -731,6 +920,13 @@ extern {
     // SSL_CIPHER
     fn SSL_CIPHER_get_id(cipher: *const SSL_CIPHER) -> c_uint;
 
+    // STACK
+    fn sk_num(sk: *const STACK) -> usize;
+    fn sk_value(sk: *const STACK, i: usize) -> *mut c_void;
+
+    // X509
+    fn i2d_X509(x509: *const X509, out: *mut *mut u8) -> c_int;
+
     // X509_VERIFY_PARAM
     fn X509_VERIFY_PARAM_set1_host(param: *mut X509_VERIFY_PARAM,
         name: *const c_char, namelen: libc::size_t) -> c_int;